@@ -6,7 +6,41 @@ use htslib;
 use bam::header;
 use bam::record;
 use bam::Reader;
+use bam::Writer;
 use bam::Read;
+use bam::ReadError;
+
+/// Output format for an alignment writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Sam,
+    Bam,
+    Cram,
+}
+
+impl Format {
+    /// The `hts_open` mode bytes that select this format for writing.
+    fn mode(&self) -> &'static [u8] {
+        match *self {
+            Format::Sam => b"w",
+            Format::Bam => b"wb",
+            Format::Cram => b"wc",
+        }
+    }
+}
+
+/// Trait for writers of alignment records, regardless of the underlying
+/// on-disk format (SAM/BAM/CRAM). Lets conversion code be written once
+/// against a `&mut dyn RecordWriter` instead of a concrete writer type.
+pub trait RecordWriter {
+    fn write(&mut self, record: &record::Record) -> Result<(), WriteError>;
+}
+
+/// Trait for readers of alignment records, regardless of the underlying
+/// on-disk format (SAM/BAM/CRAM).
+pub trait RecordReader {
+    fn read(&self, record: &mut record::Record) -> Result<bool, ReadError>;
+}
 
 // new on bam::HeaderView is not public
 pub struct SAMHeaderView {
@@ -35,6 +69,12 @@ pub struct SAMWriter {
     header: SAMHeaderView,
 }
 
+/// SAM reader.
+pub struct SAMReader {
+    f: *mut htslib::htsFile,
+    header: SAMHeaderView,
+}
+
 /// Wrapper for opening a SAM file.
 fn hts_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::htsFile, SAMError> {
     let ret = unsafe {
@@ -44,7 +84,10 @@ fn hts_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::htsFile, SAMEr
         )
     };
     if ret.is_null() {
-        Err(SAMError::IOError)
+        Err(SAMError::OpenError(
+            String::from_utf8_lossy(path.to_bytes()).into_owned(),
+            String::from_utf8_lossy(mode).into_owned(),
+        ))
     } else {
         Ok(ret)
     }
@@ -58,24 +101,85 @@ impl SAMWriter {
     /// * `path` - the path.
     /// * `header` - header definition to use
     pub fn from_path<P: AsRef<Path>>(path: P, header: &header::Header) -> Result<Self, SAMError> {
+        Self::from_path_with_format(path, header, Format::Sam, None::<&Path>)
+    }
+
+    /// Create a new SAM file at STDOUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - header definition to use
+    pub fn from_stdout(header: &header::Header) -> Result<Self, SAMError> {
+        Self::new(b"-", header, Format::Sam, None)
+    }
+
+    /// Create a new alignment file writer in the given format.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    /// * `header` - header definition to use
+    /// * `format` - the output format (SAM, BAM or CRAM)
+    /// * `reference` - path to the reference FASTA used to encode CRAM
+    ///   records; ignored for SAM/BAM, required for CRAM
+    pub fn from_path_with_format<P: AsRef<Path>, R: AsRef<Path>>(path: P, header: &header::Header, format: Format, reference: Option<R>) -> Result<Self, SAMError> {
         if let Some(p) = path.as_ref().to_str() {
-            Ok(try!(Self::new(p.as_bytes(), header)))
+            match reference {
+                Some(ref r) if format == Format::Cram => {
+                    if let Some(r) = r.as_ref().to_str() {
+                        Ok(try!(Self::new(p.as_bytes(), header, format, Some(r))))
+                    } else {
+                        Err(SAMError::OpenError(
+                            r.as_ref().display().to_string(),
+                            String::from_utf8_lossy(format.mode()).into_owned(),
+                        ))
+                    }
+                }
+                _ => Ok(try!(Self::new(p.as_bytes(), header, format, None))),
+            }
         } else {
-            Err(SAMError::IOError)
+            Err(SAMError::OpenError(
+                path.as_ref().display().to_string(),
+                String::from_utf8_lossy(format.mode()).into_owned(),
+            ))
         }
     }
 
-    /// Create a new SAM file at STDOUT.
+    /// Create a new CRAM writer, using the given FASTA file as reference.
     ///
     /// # Arguments
     ///
+    /// * `path` - the path.
     /// * `header` - header definition to use
-    pub fn from_stdout(header: &header::Header) -> Result<Self, SAMError> {
-        Self::new(b"-", header)
+    /// * `reference` - path to the reference FASTA used to encode CRAM records
+    pub fn from_path_with_reference<P: AsRef<Path>, R: AsRef<Path>>(path: P, header: &header::Header, reference: R) -> Result<Self, SAMError> {
+        Self::from_path_with_format(path, header, Format::Cram, Some(reference))
     }
 
-    fn new(path: &[u8], header: &header::Header) -> Result<Self, SAMError> {
-        let f = try!(hts_open(&ffi::CString::new(path).unwrap(), b"w"));
+    fn new(path: &[u8], header: &header::Header, format: Format, reference: Option<&str>) -> Result<Self, SAMError> {
+        let f = try!(hts_open(&ffi::CString::new(path).unwrap(), format.mode()));
+        if format == Format::Cram {
+            match reference {
+                Some(r) => {
+                    let ret = unsafe {
+                        htslib::hts_set_fai_filename(f, ffi::CString::new(r).unwrap().as_ptr())
+                    };
+                    if ret != 0 {
+                        unsafe { htslib::hts_close(f); }
+                        return Err(SAMError::OpenError(
+                            String::from_utf8_lossy(path).into_owned(),
+                            format!("cram, reference={}", r),
+                        ));
+                    }
+                }
+                None => {
+                    unsafe { htslib::hts_close(f); }
+                    return Err(SAMError::MissingReference(
+                        String::from_utf8_lossy(path).into_owned(),
+                    ));
+                }
+            }
+        }
         let header_record = unsafe {
             let header_string = header.to_bytes();
             let l_text = header_string.len();
@@ -87,6 +191,10 @@ impl SAMWriter {
                 (l_text + 1) as i32,
                 text as *const i8,
             );
+            if rec.is_null() {
+                htslib::hts_close(f);
+                return Err(SAMError::HeaderParseError);
+            }
             (*rec).text = text as *mut i8;
             (*rec).l_text = l_text as u32;
             rec
@@ -95,6 +203,21 @@ impl SAMWriter {
         Ok(SAMWriter { f: f, header: SAMHeaderView::new(header_record) })
     }
 
+    /// Use multiple threads for writing. This is a no-op for plain SAM text
+    /// output, but gives near-linear speedups for compressed BAM/CRAM output
+    /// on large files.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - number of worker threads to use
+    pub fn set_threads(&mut self, n: usize) -> Result<(), SAMError> {
+        if unsafe { htslib::hts_set_threads(self.f, n as i32) } != 0 {
+            Err(SAMError::ThreadError(n))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Write record to SAM.
     ///
     /// # Arguments
@@ -109,50 +232,73 @@ impl SAMWriter {
         }
     }
 
-    /// Read bam file. For each record apply f to it, and write to sam file if f returned Some(true), skip record if Some(false) if None then terminate iteration
+    /// Read bam file. For each record apply f to it, and write to writer if f returned Some(true), skip record if Some(false) if None then terminate iteration
     ///
     /// # Arguments
     ///
     /// * `bamfile` - the bam file to read from
-    /// * `samfile` - the sam file to write
+    /// * `writer` - the writer records are written to; any format implementing `RecordWriter` works
     /// * `f` - the predicate to apply
-    pub fn from_bam_with_filter<'a, 'b, F>(bamfile:&'a str, samfile:&'b str, f:F) -> Result<(), SAMError> where F:Fn(&record::Record) -> Option<bool> {
+    pub fn from_bam_with_filter<'a, F>(bamfile: &'a str, writer: &mut dyn RecordWriter, f: F) -> Result<(), SAMError> where F:Fn(&record::Record) -> Option<bool> {
         let bam_reader = if bamfile != "-" {
             match Reader::from_path(bamfile) {
                 Ok(bam) => bam,
-                Err(_) => return Err(SAMError::IOError)
+                Err(_) => return Err(SAMError::OpenError(bamfile.to_string(), "r".to_string()))
             }
         } else {
             match Reader::from_stdin() {
                 Ok(bam) => bam,
-                Err(_) => return Err(SAMError::IOError)
+                Err(_) => return Err(SAMError::OpenError("-".to_string(), "r".to_string()))
             }
 
         };
-        let header = header::Header::from_template(bam_reader.header());
-        let mut sam_writer = if samfile != "-" {
-                SAMWriter::from_path(samfile, &header)?
-            } else {
-                SAMWriter::from_stdout(&header)?
-            };
-        for record in bam_reader.records() {
-            if record.is_err() {
-                return Err(SAMError::IOError)
-            } 
-            let parsed = record.unwrap();
-            match f(&parsed) {
-                None => return Ok(()),
-                Some(false) => {},
-                Some(true) => if let Err(_) = sam_writer.write(&parsed) {
-                    return Err(SAMError::IOError);
-                }
+        // Go through the reader as a `&dyn RecordReader`, so this is genuinely
+        // format-agnostic conversion/filter code rather than code that only
+        // happens to work because the reader is a concrete bam::Reader.
+        let reader: &dyn RecordReader = &bam_reader;
+        let mut index = 0;
+        let mut record = record::Record::new();
+        loop {
+            match reader.read(&mut record) {
+                Ok(true) => match f(&record) {
+                    None => return Ok(()),
+                    Some(false) => {},
+                    Some(true) => try!(writer.write(&record)),
+                },
+                Ok(false) => break,
+                Err(_) => return Err(SAMError::ReadError(index)),
             }
+            index += 1;
         }
         Ok(())
     }
 
 }
 
+impl RecordWriter for SAMWriter {
+    fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
+        SAMWriter::write(self, record)
+    }
+}
+
+impl RecordReader for SAMReader {
+    fn read(&self, record: &mut record::Record) -> Result<bool, ReadError> {
+        SAMReader::read(self, record)
+    }
+}
+
+impl RecordWriter for Writer {
+    fn write(&mut self, record: &record::Record) -> Result<(), WriteError> {
+        Writer::write(self, record).or(Err(WriteError::Some))
+    }
+}
+
+impl RecordReader for Reader {
+    fn read(&self, record: &mut record::Record) -> Result<bool, ReadError> {
+        Read::read(self, record)
+    }
+}
+
 impl Drop for SAMWriter {
     fn drop(&mut self) {
         unsafe {
@@ -161,10 +307,117 @@ impl Drop for SAMWriter {
     }
 }
 
+impl SAMReader {
+    /// Open a SAM file for reading.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, SAMError> {
+        if let Some(p) = path.as_ref().to_str() {
+            Self::new(p.as_bytes())
+        } else {
+            Err(SAMError::OpenError(
+                path.as_ref().display().to_string(),
+                "r".to_string(),
+            ))
+        }
+    }
+
+    /// Read a SAM file from STDIN.
+    pub fn from_stdin() -> Result<Self, SAMError> {
+        Self::new(b"-")
+    }
+
+    fn new(path: &[u8]) -> Result<Self, SAMError> {
+        let f = try!(hts_open(&ffi::CString::new(path).unwrap(), b"r"));
+        let header = unsafe { htslib::sam_hdr_read(f) };
+        if header.is_null() {
+            return Err(SAMError::HeaderParseError);
+        }
+        Ok(SAMReader { f: f, header: SAMHeaderView::new(header) })
+    }
+
+    /// Return the SAM header.
+    pub fn header(&self) -> &SAMHeaderView {
+        &self.header
+    }
+
+    /// Read the next record into `record`.
+    ///
+    /// Returns `Ok(true)` if a record was read, `Ok(false)` at EOF.
+    ///
+    /// This is an inherent method rather than an impl of `bam::Read`:
+    /// that trait requires `header()` to return a `&bam::HeaderView`,
+    /// which `SAMReader` (backed by `SAMHeaderView`, see above) cannot
+    /// produce, since `bam::HeaderView::new` is not public.
+    pub fn read(&self, record: &mut record::Record) -> Result<bool, ReadError> {
+        match unsafe { htslib::sam_read1(self.f, self.header.inner, record.inner) } {
+            -1 => Ok(false),
+            x if x < -1 => Err(ReadError::Truncated),
+            _ => Ok(true),
+        }
+    }
+
+    /// Iterate over the records in this file.
+    pub fn records(&self) -> SAMRecords {
+        SAMRecords { reader: self }
+    }
+}
+
+/// Iterator over the records of a `SAMReader`, returned by `SAMReader::records`.
+pub struct SAMRecords<'a> {
+    reader: &'a SAMReader,
+}
+
+impl<'a> Iterator for SAMRecords<'a> {
+    type Item = Result<record::Record, ReadError>;
+
+    fn next(&mut self) -> Option<Result<record::Record, ReadError>> {
+        let mut record = record::Record::new();
+        match self.reader.read(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Drop for SAMReader {
+    fn drop(&mut self) {
+        unsafe {
+            htslib::hts_close(self.f);
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum SAMError {
-        IOError {}
+        OpenError(path: String, mode: String) {
+            description("error opening file")
+            display("error opening {:?} in mode {:?}", path, mode)
+        }
+        MissingReference(path: String) {
+            description("CRAM output requires a reference FASTA")
+            display("{:?}: CRAM output requires a reference FASTA (see from_path_with_reference)", path)
+        }
+        HeaderParseError {
+            description("error parsing header")
+        }
+        ReadError(index: usize) {
+            description("error reading record")
+            display("error reading record at index {}", index)
+        }
+        ThreadError(n: usize) {
+            description("error setting up thread pool")
+            display("error setting up a thread pool of {} threads", n)
+        }
+        WriteError(err: WriteError) {
+            description("error writing record")
+            display("error writing record: {}", err)
+            from()
+        }
     }
 }
 
@@ -203,6 +456,98 @@ fn test_sam_writer_example() {
     assert_eq!(expected, written);
 }
 
+#[test]
+fn test_sam_reader_round_trip() {
+    use std::fs::File;
+    use std::io::Read as IoRead;
+    let bamfile = "./test/bam2sam_test.bam";
+    let samfile = "./test/bam2sam_roundtrip_src.sam";
+    let roundtrip_file = "./test/bam2sam_roundtrip.sam";
+
+    let bam_reader = Reader::from_path(bamfile).unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    let mut sam_writer = SAMWriter::from_path(samfile, &header).unwrap();
+    for record in bam_reader.records() {
+        sam_writer.write(&record.unwrap()).unwrap();
+    }
+    drop(sam_writer);
+
+    // read the SAM we just wrote back through SAMReader, and write it out again
+    let sam_reader = SAMReader::from_path(samfile).unwrap();
+    let mut roundtrip_writer = SAMWriter::from_path(roundtrip_file, &header).unwrap();
+    for record in sam_reader.records() {
+        roundtrip_writer.write(&record.unwrap()).unwrap();
+    }
+    drop(roundtrip_writer);
+
+    let mut original = Vec::new();
+    let mut roundtrip = Vec::new();
+    File::open(samfile).unwrap().read_to_end(&mut original).unwrap();
+    File::open(roundtrip_file).unwrap().read_to_end(&mut roundtrip).unwrap();
+    assert_eq!(original, roundtrip);
+}
+
+#[test]
+fn test_from_bam_with_filter_record_writer() {
+    use std::fs::File;
+    use std::io::Read as IoRead;
+    let bamfile = "./test/bam2sam_test.bam";
+    let samfile = "./test/bam2sam_out_filter.sam";
+    let expectedfile = "./test/bam2sam_expected.sam";
+
+    let bam_reader = Reader::from_path(bamfile).unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    let mut sam_writer = SAMWriter::from_path(samfile, &header).unwrap();
+    // passed as `&mut dyn RecordWriter`, exercising the trait object path
+    SAMWriter::from_bam_with_filter(bamfile, &mut sam_writer, |_| Some(true)).unwrap();
+    drop(sam_writer);
+
+    let mut expected = Vec::new();
+    let mut written = Vec::new();
+    File::open(expectedfile).unwrap().read_to_end(&mut expected).unwrap();
+    File::open(samfile).unwrap().read_to_end(&mut written).unwrap();
+    assert_eq!(expected, written);
+}
+
+#[test]
+fn test_format_bam_writer() {
+    let bam_reader = Reader::from_path("./test/bam2sam_test.bam").unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    let writer = SAMWriter::from_path_with_format("./test/bam2sam_out.bam", &header, Format::Bam, None::<&Path>);
+    assert!(writer.is_ok());
+}
+
+#[test]
+fn test_format_cram_requires_reference() {
+    let bam_reader = Reader::from_path("./test/bam2sam_test.bam").unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    match SAMWriter::from_path_with_format("./test/bam2sam_out.cram", &header, Format::Cram, None::<&Path>) {
+        Err(SAMError::MissingReference(..)) => {},
+        other => panic!("expected MissingReference for CRAM without a reference, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_format_cram_writer_with_reference() {
+    let bam_reader = Reader::from_path("./test/bam2sam_test.bam").unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    let reference = "./test/bam2sam_test_reference.fasta";
+    let mut writer = SAMWriter::from_path_with_reference(
+        "./test/bam2sam_out_ref.cram", &header, reference,
+    ).unwrap();
+    for record in bam_reader.records() {
+        writer.write(&record.unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn test_set_threads() {
+    let bam_reader = Reader::from_path("./test/bam2sam_test.bam").unwrap();
+    let header = header::Header::from_template(bam_reader.header());
+    let mut writer = SAMWriter::from_path("./test/bam2sam_threads.sam", &header).unwrap();
+    assert!(writer.set_threads(2).is_ok());
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum WriteError {